@@ -0,0 +1,207 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use dragonfly_client::shutdown;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// INITIAL_BACKOFF is the delay before the first restart attempt of a worker.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// MAX_BACKOFF is the cap the restart backoff doubles up to.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// STABLE_RUN_THRESHOLD is how long a worker must run before a subsequent failure resets
+/// its backoff back to `INITIAL_BACKOFF`, instead of continuing to double.
+const STABLE_RUN_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// WorkerFuture is the boxed future returned by a worker's task factory.
+type WorkerFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Worker is a single named long-lived task supervised by the [`BackgroundRunner`]. `task`
+/// is a factory rather than a one-shot future because a restarted worker needs a fresh
+/// future each attempt.
+struct Worker {
+    name: &'static str,
+    restartable: bool,
+    task: Box<dyn Fn() -> WorkerFuture + Send + Sync>,
+}
+
+/// BackgroundRunner owns the daemon's set of named long-lived workers (dynconfig, gc,
+/// announcers, grpc servers, proxy, health/metrics/stats) and supervises them so that an
+/// unexpected exit in one does not tear down the whole daemon.
+///
+/// Each worker runs in a restart loop: on unexpected exit (error or panic) it is restarted
+/// with exponential backoff starting at [`INITIAL_BACKOFF`] and capped at [`MAX_BACKOFF`],
+/// which resets back to [`INITIAL_BACKOFF`] once the worker has stayed up for at least
+/// [`STABLE_RUN_THRESHOLD`]. A worker whose future returns `Ok(())` is treated as finished
+/// and is not restarted, whether or not shutdown has been triggered. Workers registered with
+/// `restartable = false` are treated as genuinely fatal and are not restarted. The runner
+/// holds the shutdown handle and propagates it to every worker; `BackgroundRunner::run`
+/// returns only once every worker has finished.
+pub struct BackgroundRunner {
+    shutdown: shutdown::Shutdown,
+    shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    workers: Vec<Worker>,
+}
+
+impl BackgroundRunner {
+    /// new creates an empty `BackgroundRunner`.
+    pub fn new(
+        shutdown: shutdown::Shutdown,
+        shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    ) -> Self {
+        Self {
+            shutdown,
+            shutdown_complete_tx,
+            workers: Vec::new(),
+        }
+    }
+
+    /// register adds a worker with the given name and restartability, driven by a task
+    /// factory that produces a fresh future for every (re)start attempt.
+    pub fn register<F, Fut>(&mut self, name: &'static str, restartable: bool, task: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.workers.push(Worker {
+            name,
+            restartable,
+            task: Box::new(move || Box::pin(task())),
+        });
+    }
+
+    /// run spawns every registered worker in its own supervised restart loop and waits for
+    /// all of them to finish. A worker finishes once shutdown has been triggered, once its
+    /// future returns `Ok(())`, or, for non-restartable workers, once it has exited with an
+    /// error or a panic; this future stays pending until every worker has finished.
+    pub async fn run(self) {
+        let shutdown_complete_tx = self.shutdown_complete_tx.clone();
+        let handles: Vec<_> = self
+            .workers
+            .into_iter()
+            .map(|worker| {
+                let shutdown = self.shutdown.clone();
+                let shutdown_complete_tx = shutdown_complete_tx.clone();
+                tokio::spawn(Self::supervise(worker, shutdown, shutdown_complete_tx))
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    /// supervise runs a single worker in a loop, restarting it with exponential backoff when
+    /// it exits with an error or a panic, until shutdown is triggered, the worker returns
+    /// `Ok(())`, or the worker is fatal.
+    async fn supervise(
+        worker: Worker,
+        mut shutdown: shutdown::Shutdown,
+        _shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    ) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started_at = Instant::now();
+            let result = tokio::spawn((worker.task)()).await;
+
+            if shutdown.is_shutdown() {
+                info!("worker {} stopped after shutdown", worker.name);
+                return;
+            }
+
+            match result {
+                Ok(Ok(())) => {
+                    info!("worker {} exited cleanly", worker.name);
+                    return;
+                }
+                Ok(Err(err)) => {
+                    error!("worker {} failed: {}", worker.name, err);
+                }
+                Err(err) => {
+                    error!("worker {} panicked: {}", worker.name, err);
+                }
+            }
+
+            if !worker.restartable {
+                error!("worker {} is not restartable, giving up", worker.name);
+                return;
+            }
+
+            // Measured before the backoff sleep, so the sleep itself does not count as run time.
+            let ran_stable = started_at.elapsed() >= STABLE_RUN_THRESHOLD;
+
+            warn!(
+                "restarting worker {} in {:?} after unexpected exit",
+                worker.name, backoff
+            );
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = shutdown.recv() => {
+                    info!("worker {} shutting down during restart backoff", worker.name);
+                    return;
+                }
+            }
+
+            backoff = next_backoff(backoff, ran_stable);
+        }
+    }
+}
+
+/// next_backoff computes the backoff to use for a worker's *next* restart, given the
+/// backoff it just slept through and whether it ran for at least [`STABLE_RUN_THRESHOLD`]
+/// before this exit. Pulled out of `supervise` so the doubling/reset policy can be tested
+/// without driving real sleeps.
+fn next_backoff(previous: Duration, ran_stable: bool) -> Duration {
+    if ran_stable {
+        INITIAL_BACKOFF
+    } else {
+        (previous * 2).min(MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_backoff_doubles_up_to_max_when_not_stable() {
+        let mut backoff = INITIAL_BACKOFF;
+        for _ in 0..10 {
+            backoff = next_backoff(backoff, false);
+        }
+        assert_eq!(backoff, MAX_BACKOFF);
+    }
+
+    #[test]
+    fn next_backoff_resets_to_initial_when_stable() {
+        assert_eq!(
+            next_backoff(MAX_BACKOFF, true),
+            INITIAL_BACKOFF
+        );
+    }
+
+    #[test]
+    fn next_backoff_never_exceeds_max() {
+        assert_eq!(next_backoff(MAX_BACKOFF, false), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// default_pid_path returns the default location for dfdaemon's pid file when `--pid-file`
+/// is not given.
+pub fn default_pid_path() -> PathBuf {
+    PathBuf::from("/var/run/dfdaemon.pid")
+}
+
+/// PidFile guards a PID file for the lifetime of the daemon process, refusing to start if
+/// another live daemon already holds it, and removing it on clean exit (via `Drop`) or
+/// explicit `remove`.
+///
+/// Two daemons racing to start concurrently can both observe the same stale (or absent) pid
+/// file before either has written to it, so a `kill(pid, 0)` liveness check alone is not
+/// enough to rule out the race. `file` holds an exclusive, non-blocking `flock` for as long
+/// as the daemon runs; the kernel releases it automatically if the process dies without
+/// cleaning up, so a crashed daemon's pid file is still safely reclaimable by the next one.
+pub struct PidFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl PidFile {
+    /// create takes an exclusive advisory lock on `path` and writes it with the current
+    /// process id, failing if another live process already holds the lock.
+    pub fn create(path: PathBuf) -> Result<Self, io::Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        // SAFETY: `file` is a valid, exclusively-owned fd for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                let pid = fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|contents| contents.trim().parse::<i32>().ok());
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    match pid {
+                        Some(pid) => format!(
+                            "pid file {} is locked by another running instance (pid {})",
+                            path.display(),
+                            pid
+                        ),
+                        None => format!(
+                            "pid file {} is locked by another running instance",
+                            path.display()
+                        ),
+                    },
+                ));
+            }
+            return Err(err);
+        }
+
+        let mut file = file;
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        file.flush()?;
+        Ok(Self { path, file })
+    }
+
+    /// remove deletes the pid file, tolerating it already being gone.
+    pub fn remove(&self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                tracing::warn!("failed to remove pid file {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unique_pid_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "dfdaemon-test-{}-{}-{}.pid",
+            name,
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    #[test]
+    fn create_writes_current_pid() {
+        let path = unique_pid_path("writes-current-pid");
+        let pid_file = PidFile::create(path.clone()).expect("create should succeed");
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, std::process::id().to_string());
+        drop(pid_file);
+    }
+
+    #[test]
+    fn create_fails_while_another_handle_holds_the_lock() {
+        let path = unique_pid_path("second-create-fails");
+        let first = PidFile::create(path.clone()).expect("first create should succeed");
+
+        let err = PidFile::create(path.clone()).expect_err("second create should fail");
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        drop(first);
+    }
+
+    #[test]
+    fn create_reclaims_the_path_once_the_lock_is_released() {
+        let path = unique_pid_path("reclaims-after-drop");
+        let first = PidFile::create(path.clone()).expect("first create should succeed");
+        drop(first);
+
+        // The previous holder released its lock (simulating a clean exit, or the kernel
+        // reclaiming the lock after a crash), so a fresh create should succeed rather than
+        // being fooled by the stale pid contents left on disk.
+        let second = PidFile::create(path.clone()).expect("create after drop should succeed");
+        drop(second);
+    }
+
+    #[test]
+    fn create_succeeds_over_a_stale_unlocked_pid_file() {
+        let path = unique_pid_path("stale-unlocked");
+        fs::write(&path, "999999999").unwrap();
+
+        let pid_file = PidFile::create(path.clone()).expect("create over stale file should succeed");
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, std::process::id().to_string());
+        drop(pid_file);
+    }
+
+    #[test]
+    fn remove_deletes_the_pid_file() {
+        let path = unique_pid_path("remove-deletes-file");
+        let pid_file = PidFile::create(path.clone()).expect("create should succeed");
+        pid_file.remove();
+        assert!(!path.exists());
+    }
+}
+
+/// daemonize detaches the current process from its controlling terminal and re-parents it
+/// under init, redirecting stdio to `log_dir` so a `--daemon` run keeps logging instead of
+/// writing to a terminal that no longer exists.
+#[cfg(unix)]
+pub fn daemonize(log_dir: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(log_dir)?;
+
+    daemonize::Daemonize::new()
+        .stdout(fs::File::create(log_dir.join("dfdaemon.stdout.log"))?)
+        .stderr(fs::File::create(log_dir.join("dfdaemon.stderr.log"))?)
+        .start()
+        .map_err(|err| io::Error::other(format!("daemonize failed: {}", err)))
+}
+
+/// raise_fd_limit raises the process's `RLIMIT_NOFILE` soft limit toward the hard limit,
+/// logging the old and new limits. A seed peer serving many concurrent piece connections
+/// routinely exhausts the default per-process file descriptor budget.
+#[cfg(unix)]
+pub fn raise_fd_limit() -> Result<(), io::Error> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    // SAFETY: `limit` is a valid, exclusively-owned `rlimit` for the duration of the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let old_soft = limit.rlim_cur;
+    if limit.rlim_cur >= limit.rlim_max {
+        info!(
+            "fd limit already at hard limit ({} open files), not raising",
+            old_soft
+        );
+        return Ok(());
+    }
+
+    limit.rlim_cur = limit.rlim_max;
+
+    // SAFETY: `limit` is a valid, exclusively-owned `rlimit` for the duration of the call.
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    info!(
+        "raised fd limit from {} to {} open files",
+        old_soft, limit.rlim_cur
+    );
+    Ok(())
+}
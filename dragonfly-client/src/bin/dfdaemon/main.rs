@@ -14,6 +14,10 @@
  * limitations under the License.
  */
 
+mod background_runner;
+mod daemonize;
+
+use background_runner::BackgroundRunner;
 use clap::Parser;
 use dragonfly_client::announcer::{ManagerAnnouncer, SchedulerAnnouncer};
 use dragonfly_client::dynconfig::Dynconfig;
@@ -22,6 +26,8 @@ use dragonfly_client::grpc::{
     dfdaemon_download::DfdaemonDownloadServer, dfdaemon_upload::DfdaemonUploadServer,
     manager::ManagerClient, scheduler::SchedulerClient,
 };
+#[cfg(feature = "http3-preview")]
+use dragonfly_client::grpc::quic::QuicConfig;
 use dragonfly_client::health::Health;
 use dragonfly_client::metrics::Metrics;
 use dragonfly_client::proxy::Proxy;
@@ -36,11 +42,16 @@ use dragonfly_client_util::id_generator::IDGenerator;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use termion::{color, style};
 use tokio::sync::mpsc;
 use tokio::sync::Barrier;
 use tracing::{error, info, Level};
 
+/// ADDRS_REFRESH_INTERVAL is how often the manager and scheduler candidate addresses are
+/// re-synced in the background.
+const ADDRS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
@@ -94,6 +105,42 @@ struct Args {
     #[arg(long, default_value_t = true, help = "Specify whether to print log")]
     console: bool,
 
+    #[arg(
+        short = 'd',
+        long = "daemon",
+        default_value_t = false,
+        help = "Run dfdaemon in the background, detached from the controlling terminal"
+    )]
+    daemon: bool,
+
+    #[arg(
+        long = "pid-file",
+        default_value_os_t = daemonize::default_pid_path(),
+        help = "Specify the pid file path, used to refuse starting a second instance"
+    )]
+    pid_file: PathBuf,
+
+    #[cfg(feature = "http3-preview")]
+    #[arg(
+        long = "proxy-quic-addr",
+        help = "Enable the opt-in QUIC preview listener for the proxy, bound to this address"
+    )]
+    proxy_quic_addr: Option<SocketAddr>,
+
+    #[cfg(feature = "http3-preview")]
+    #[arg(
+        long = "quic-cert",
+        help = "Specify the TLS certificate (PEM) used for the QUIC preview listeners"
+    )]
+    quic_cert: Option<PathBuf>,
+
+    #[cfg(feature = "http3-preview")]
+    #[arg(
+        long = "quic-key",
+        help = "Specify the TLS private key (PEM) used for the QUIC preview listeners"
+    )]
+    quic_key: Option<PathBuf>,
+
     #[arg(
         short = 'V',
         long = "version",
@@ -105,11 +152,31 @@ struct Args {
     version: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    // Parse command line arguments.
+/// main parses arguments and, if `--daemon` was given, forks into the background before
+/// anything else runs. That ordering matters: once a multi-thread Tokio runtime has been
+/// built, it has already spawned its worker OS threads, and `fork()` only carries the
+/// calling thread into the child — every other worker thread (and anything it held locked)
+/// simply vanishes, leaving the daemonized child in an unpredictable state. `args.log_dir`
+/// comes straight off the CLI, so daemonizing can happen before the runtime — and all async
+/// code, including config loading — is ever constructed.
+fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
 
+    if args.daemon {
+        daemonize::daemonize(&args.log_dir).inspect_err(|err| {
+            error!("daemonize failed: {}", err);
+        })?;
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+/// run is the rest of dfdaemon's startup and main loop, entered only once the process is
+/// already in its final (possibly daemonized) form and a Tokio runtime is available.
+async fn run(args: Args) -> Result<(), anyhow::Error> {
     // Load config.
     let config = match dfdaemon::Config::load(&args.config).await {
         Ok(config) => config,
@@ -139,7 +206,9 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let config = Arc::new(config);
 
-    // Initialize tracing.
+    // Initialize tracing first: every `info!`/`error!` call below, including the pid file
+    // and fd limit ones immediately following, would otherwise be a silent no-op, since no
+    // tracing subscriber exists until this has run.
     let _guards = init_tracing(
         dfdaemon::NAME,
         args.log_dir.clone(),
@@ -154,6 +223,17 @@ async fn main() -> Result<(), anyhow::Error> {
         args.console,
     );
 
+    // Create the pid file, refusing to start if a live instance is already running.
+    let _pid_file = daemonize::PidFile::create(args.pid_file.clone()).inspect_err(|err| {
+        error!("create pid file failed: {}", err);
+    })?;
+
+    // Raise the open file descriptor limit towards its hard cap, since a seed peer serving
+    // many concurrent piece connections routinely exhausts the default budget.
+    if let Err(err) = daemonize::raise_fd_limit() {
+        error!("raise fd limit failed: {}", err);
+    }
+
     // Initialize storage.
     let storage = Storage::new(config.clone(), config.storage.dir.as_path(), args.log_dir)
         .await
@@ -196,7 +276,7 @@ async fn main() -> Result<(), anyhow::Error> {
     let dynconfig = Arc::new(dynconfig);
 
     // Initialize scheduler client.
-    let scheduler_client = SchedulerClient::new(config.clone(), dynconfig.clone())
+    let scheduler_client = SchedulerClient::new(dynconfig.clone())
         .await
         .inspect_err(|err| {
             error!("initialize scheduler client failed: {}", err);
@@ -250,13 +330,15 @@ async fn main() -> Result<(), anyhow::Error> {
         shutdown_complete_tx.clone(),
     );
 
-    // Initialize proxy server.
-    let proxy = Proxy::new(
-        config.clone(),
-        task.clone(),
-        shutdown.clone(),
-        shutdown_complete_tx.clone(),
-    );
+    // Resolve the proxy's opt-in QUIC listener config, if any, so proxied client requests
+    // can ride QUIC instead of being limited to TCP's head-of-line blocking on lossy,
+    // high-latency links. `config.proxy.quic` is the primary way to opt in; the
+    // `--proxy-quic-addr`/`--quic-cert`/`--quic-key` flags exist to override it (or to opt
+    // in without touching the config file at all). The `Proxy` itself is constructed fresh
+    // per (re)start attempt below, by the background runner, rather than built once here.
+    #[cfg(feature = "http3-preview")]
+    let proxy_quic_config =
+        resolve_quic_config(config.proxy.quic.clone(), args.proxy_quic_addr, &args)?;
 
     // Initialize manager announcer.
     let manager_announcer = ManagerAnnouncer::new(
@@ -279,26 +361,6 @@ async fn main() -> Result<(), anyhow::Error> {
         error!("initialize scheduler announcer failed: {}", err);
     })?;
 
-    // Initialize upload grpc server.
-    let mut dfdaemon_upload_grpc = DfdaemonUploadServer::new(
-        config.clone(),
-        SocketAddr::new(config.upload.server.ip.unwrap(), config.upload.server.port),
-        task.clone(),
-        persistent_cache_task.clone(),
-        shutdown.clone(),
-        shutdown_complete_tx.clone(),
-    );
-
-    // Initialize download grpc server.
-    let mut dfdaemon_download_grpc = DfdaemonDownloadServer::new(
-        config.clone(),
-        config.download.server.socket_path.clone(),
-        task.clone(),
-        persistent_cache_task.clone(),
-        shutdown.clone(),
-        shutdown_complete_tx.clone(),
-    );
-
     // Initialize garbage collector.
     let gc = GC::new(
         config.clone(),
@@ -312,64 +374,228 @@ async fn main() -> Result<(), anyhow::Error> {
     // Log dfdaemon started pid.
     info!("dfdaemon started at pid {}", std::process::id());
 
-    // grpc server started barrier.
-    let grpc_server_started_barrier = Arc::new(Barrier::new(3));
-
-    // Wait for servers to exit or shutdown signal.
-    tokio::select! {
-        _ = tokio::spawn(async move { dynconfig.run().await }) => {
-            info!("dynconfig manager exited");
-        },
-
-        _ = tokio::spawn(async move { health.run().await }) => {
-            info!("health server exited");
-        },
-
-        _ = tokio::spawn(async move { metrics.run().await }) => {
-            info!("metrics server exited");
-        },
+    // grpc server started barrier. One arrival per listener (upload over TCP, download over
+    // its unix socket, proxy over TCP), plus one more if the proxy's opt-in QUIC listener is
+    // enabled so startup stays coordinated.
+    #[allow(unused_mut)]
+    let mut grpc_server_started_barrier_count = 3;
+    #[cfg(feature = "http3-preview")]
+    if proxy_quic_config.is_some() {
+        grpc_server_started_barrier_count += 1;
+    }
+    let grpc_server_started_barrier = Arc::new(Barrier::new(grpc_server_started_barrier_count));
+
+    // Register every long-lived subsystem with the background runner, which supervises
+    // each one in its own restart loop instead of letting a single unexpected exit tear
+    // down the whole daemon. A worker is restarted when it returns an error or panics; the
+    // dynconfig, health, metrics, stats, scheduler announcer and GC workers below map any
+    // return of their `run` to `Ok(())`, so the runner treats it as finished and does not
+    // restart them.
+    let mut background_runner = BackgroundRunner::new(shutdown.clone(), shutdown_complete_tx.clone());
+
+    background_runner.register("dynconfig", true, move || {
+        let dynconfig = dynconfig.clone();
+        async move {
+            dynconfig.run().await;
+            Ok(())
+        }
+    });
 
-        _ = tokio::spawn(async move { stats.run().await }) => {
-            info!("stats server exited");
-        },
+    background_runner.register("health", true, move || {
+        let health = health.clone();
+        async move {
+            health.run().await;
+            Ok(())
+        }
+    });
 
-        _ = tokio::spawn(async move { manager_announcer.run().await.unwrap_or_else(|err| error!("announcer manager failed: {}", err))} ) => {
-            info!("announcer manager exited");
-        },
+    background_runner.register("metrics", true, move || {
+        let metrics = metrics.clone();
+        async move {
+            metrics.run().await;
+            Ok(())
+        }
+    });
 
-        _ = tokio::spawn(async move { scheduler_announcer.run().await }) => {
-            info!("announcer scheduler exited");
-        },
+    background_runner.register("stats", true, move || {
+        let stats = stats.clone();
+        async move {
+            stats.run().await;
+            Ok(())
+        }
+    });
+
+    background_runner.register("announcer_manager", true, move || {
+        let manager_announcer = manager_announcer.clone();
+        async move { manager_announcer.run().await.map_err(Into::into) }
+    });
+
+    background_runner.register("announcer_scheduler", true, move || {
+        let scheduler_announcer = scheduler_announcer.clone();
+        async move {
+            scheduler_announcer.run().await;
+            Ok(())
+        }
+    });
 
-        _ = tokio::spawn(async move { gc.run().await }) => {
-            info!("garbage collector exited");
-        },
+    background_runner.register("gc", true, move || {
+        let gc = gc.clone();
+        async move {
+            gc.run().await;
+            Ok(())
+        }
+    });
+
+    // Periodically re-sync the manager and scheduler candidate addresses, so a scheduler
+    // cluster change `Dynconfig` has already observed is reflected in the scheduler client's
+    // connection pool without restarting the daemon. The manager addresses come from the
+    // config loaded at startup, so their refresh leaves the manager pool unchanged.
+    background_runner.register("addrs_refresh", true, {
+        let manager_client = manager_client.clone();
+        let scheduler_client = scheduler_client.clone();
+        let shutdown = shutdown.clone();
+
+        move || {
+            let manager_client = manager_client.clone();
+            let scheduler_client = scheduler_client.clone();
+            let mut shutdown = shutdown.clone();
+
+            async move {
+                let mut interval = tokio::time::interval(ADDRS_REFRESH_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            manager_client.refresh_addrs().await;
+                            if let Err(err) = scheduler_client.refresh_addrs().await {
+                                error!("refresh scheduler addrs failed: {}", err);
+                            }
+                        }
+                        _ = shutdown.recv() => {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    // `DfdaemonUploadServer` and `Proxy` are not `Clone`, and a restarted worker must not
+    // re-arrive at the same startup barrier the other grpc servers already passed (it would
+    // block forever waiting for arrivals that already happened). So each of these factories
+    // builds a brand new server from its cheap `Arc`/config inputs on every (re)start, and
+    // only hands the real `grpc_server_started_barrier` to attempts before the first one
+    // that actually reaches it — later attempts get a throwaway single-party barrier that is
+    // satisfied by this worker alone. `barrier_reached` is flipped by `run` itself only once
+    // an attempt's arrival genuinely lands, not eagerly when the attempt merely starts: an
+    // attempt that fails before binding (e.g. a TCP bind error) must not consume the real
+    // barrier's only arrival for this worker.
+    background_runner.register("dfdaemon_upload_grpc", true, {
+        let config = config.clone();
+        let task = task.clone();
+        let persistent_cache_task = persistent_cache_task.clone();
+        let shutdown = shutdown.clone();
+        let shutdown_complete_tx = shutdown_complete_tx.clone();
+        let grpc_server_started_barrier = grpc_server_started_barrier.clone();
+        let barrier_reached = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        move || {
+            let mut dfdaemon_upload_grpc = DfdaemonUploadServer::new(
+                SocketAddr::new(config.upload.server.ip.unwrap(), config.upload.server.port),
+                task.clone(),
+                persistent_cache_task.clone(),
+                shutdown.clone(),
+                shutdown_complete_tx.clone(),
+            );
 
-        _ = {
-            let barrier = grpc_server_started_barrier.clone();
-            tokio::spawn(async move {
-                dfdaemon_upload_grpc.run(barrier).await.unwrap_or_else(|err| error!("dfdaemon upload grpc server failed: {}", err));
-            })
-        } => {
-            info!("dfdaemon upload grpc server exited");
-        },
+            let barrier = if barrier_reached.load(std::sync::atomic::Ordering::SeqCst) {
+                Arc::new(Barrier::new(1))
+            } else {
+                grpc_server_started_barrier.clone()
+            };
+            let barrier_reached = barrier_reached.clone();
+
+            async move {
+                dfdaemon_upload_grpc
+                    .run(barrier, barrier_reached)
+                    .await
+                    .map_err(Into::into)
+            }
+        }
+    });
+
+    background_runner.register("dfdaemon_download_grpc", true, {
+        let config = config.clone();
+        let task = task.clone();
+        let persistent_cache_task = persistent_cache_task.clone();
+        let shutdown = shutdown.clone();
+        let shutdown_complete_tx = shutdown_complete_tx.clone();
+        let grpc_server_started_barrier = grpc_server_started_barrier.clone();
+        let barrier_reached = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        move || {
+            let mut dfdaemon_download_grpc = DfdaemonDownloadServer::new(
+                config.download.server.socket_path.clone(),
+                task.clone(),
+                persistent_cache_task.clone(),
+                shutdown.clone(),
+                shutdown_complete_tx.clone(),
+            );
 
-        _ = {
-            let barrier = grpc_server_started_barrier.clone();
-            tokio::spawn(async move {
-                dfdaemon_download_grpc.run(barrier).await.unwrap_or_else(|err| error!("dfdaemon download grpc server failed: {}", err));
-            })
-        } => {
-            info!("dfdaemon download grpc unix server exited");
-        },
+            let barrier = if barrier_reached.load(std::sync::atomic::Ordering::SeqCst) {
+                Arc::new(Barrier::new(1))
+            } else {
+                grpc_server_started_barrier.clone()
+            };
+            let barrier_reached = barrier_reached.clone();
+
+            async move {
+                dfdaemon_download_grpc
+                    .run(barrier, barrier_reached)
+                    .await
+                    .map_err(Into::into)
+            }
+        }
+    });
+
+    background_runner.register("proxy", true, {
+        let config = config.clone();
+        let task = task.clone();
+        let shutdown = shutdown.clone();
+        let shutdown_complete_tx = shutdown_complete_tx.clone();
+        let grpc_server_started_barrier = grpc_server_started_barrier.clone();
+        let barrier_reached = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        #[cfg(feature = "http3-preview")]
+        let proxy_quic_config = proxy_quic_config.clone();
+
+        move || {
+            #[allow(unused_mut)]
+            let mut proxy = Proxy::new(
+                config.clone(),
+                task.clone(),
+                shutdown.clone(),
+                shutdown_complete_tx.clone(),
+            );
+            #[cfg(feature = "http3-preview")]
+            if let Some(quic_config) = proxy_quic_config.clone() {
+                proxy = proxy.with_quic(quic_config);
+            }
+
+            let barrier = if barrier_reached.load(std::sync::atomic::Ordering::SeqCst) {
+                Arc::new(Barrier::new(1))
+            } else {
+                grpc_server_started_barrier.clone()
+            };
+            let barrier_reached = barrier_reached.clone();
+
+            async move { proxy.run(barrier, barrier_reached).await.map_err(Into::into) }
+        }
+    });
 
-        _ = {
-            let barrier = grpc_server_started_barrier.clone();
-            tokio::spawn(async move {
-                proxy.run(barrier).await.unwrap_or_else(|err| error!("proxy server failed: {}", err));
-            })
-        } => {
-            info!("proxy server exited");
+    // Wait for the background runner to exit (it does so once every worker has finished)
+    // or for the shutdown signal itself.
+    tokio::select! {
+        _ = background_runner.run() => {
+            info!("background runner exited");
         },
 
         _ = shutdown::shutdown_signal() => {},
@@ -398,3 +624,50 @@ async fn main() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+/// quic_cert_and_key returns the TLS certificate and key paths required to bind a QUIC
+/// listener, once at least one `--*-quic-addr` flag has opted a server in without a
+/// `config.*.quic` section already supplying them.
+#[cfg(feature = "http3-preview")]
+fn quic_cert_and_key(args: &Args) -> Result<(PathBuf, PathBuf), anyhow::Error> {
+    let cert_path = args
+        .quic_cert
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--quic-cert is required when a QUIC listener is enabled"))?;
+    let key_path = args
+        .quic_key
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--quic-key is required when a QUIC listener is enabled"))?;
+    Ok((cert_path, key_path))
+}
+
+/// resolve_quic_config merges a server's `config.*.quic` file section with its
+/// `--*-quic-addr` CLI override into the `QuicConfig` its listener is bound from.
+/// `config_section` is the primary way to opt in; the CLI flag, when given, overrides just
+/// the address (letting an operator redirect a listener without editing the config file),
+/// or opts the server into QUIC on its own when no config section is present, falling back
+/// to `--quic-cert`/`--quic-key` for the TLS material in that case.
+#[cfg(feature = "http3-preview")]
+fn resolve_quic_config(
+    config_section: Option<QuicConfig>,
+    cli_addr: Option<SocketAddr>,
+    args: &Args,
+) -> Result<Option<QuicConfig>, anyhow::Error> {
+    match (config_section, cli_addr) {
+        (Some(mut quic_config), cli_addr) => {
+            if let Some(addr) = cli_addr {
+                quic_config.addr = addr;
+            }
+            Ok(Some(quic_config))
+        }
+        (None, Some(addr)) => {
+            let (cert_path, key_path) = quic_cert_and_key(args)?;
+            Ok(Some(QuicConfig {
+                addr,
+                cert_path,
+                key_path,
+            }))
+        }
+        (None, None) => Ok(None),
+    }
+}
@@ -0,0 +1,504 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Shared connection pooling and failover used by both [`crate::grpc::manager::ManagerClient`]
+//! and [`crate::grpc::scheduler::SchedulerClient`].
+//!
+//! A client that resolves its endpoint once at startup has no graceful recovery path from a
+//! transient manager or scheduler outage, or from an address rotation pushed down from
+//! `Dynconfig`. `ConnectionManager` instead keeps a pool of candidate addresses, tracks
+//! per-endpoint health, lazily reconnects with jittered exponential backoff, and lets a
+//! caller fail a given RPC over to the next healthy address on transport errors.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+use tracing::{info, warn};
+
+lazy_static! {
+    /// ENDPOINT_CALL_TOTAL counts calls to a candidate endpoint, labeled by its address and
+    /// outcome ("success" or "failure"), so per-endpoint reliability is visible without
+    /// having to grep through tracing logs.
+    static ref ENDPOINT_CALL_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "dragonfly_client_connection_manager_endpoint_call_total",
+        "Total number of calls to a candidate endpoint, by address and outcome.",
+        &["addr", "outcome"]
+    )
+    .unwrap();
+
+    /// ENDPOINT_EJECTED reports, per candidate address, whether it is currently ejected
+    /// from the healthy rotation (1) or not (0).
+    static ref ENDPOINT_EJECTED: IntGaugeVec = register_int_gauge_vec!(
+        "dragonfly_client_connection_manager_endpoint_ejected",
+        "Whether a candidate endpoint is currently ejected from the healthy rotation.",
+        &["addr"]
+    )
+    .unwrap();
+}
+
+/// INITIAL_BACKOFF is the delay before the first reconnect attempt to an endpoint.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// MAX_BACKOFF is the cap jittered reconnect backoff doubles up to.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// CONSECUTIVE_FAILURES_TO_EJECT is how many consecutive failed RPCs/connect attempts an
+/// endpoint tolerates before it is temporarily ejected from the healthy rotation.
+const CONSECUTIVE_FAILURES_TO_EJECT: u32 = 3;
+
+/// RE_PROBE_INTERVAL is how long an ejected endpoint stays out of the healthy rotation
+/// before it is ranked as a candidate again and the next call to it re-probes it. There is
+/// no background probing.
+const RE_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// EndpointHealth tracks the liveness of a single candidate address.
+#[derive(Debug, Clone, Default)]
+struct EndpointHealth {
+    consecutive_failures: u32,
+    last_success_at: Option<Instant>,
+    last_failure_at: Option<Instant>,
+    ejected_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl EndpointHealth {
+    fn is_ejected(&self) -> bool {
+        match self.ejected_at {
+            Some(at) => at.elapsed() < RE_PROBE_INTERVAL,
+            None => false,
+        }
+    }
+
+    /// is_throttled reports whether this endpoint is still serving out its backoff window
+    /// since its last failure, and should be deprioritized in favor of a candidate that
+    /// isn't.
+    fn is_throttled(&self) -> bool {
+        self.remaining_backoff().is_some()
+    }
+
+    /// remaining_backoff returns how much longer this endpoint should be left alone before
+    /// the next connect attempt, if it is still serving out its backoff window since its
+    /// last failure.
+    fn remaining_backoff(&self) -> Option<Duration> {
+        let elapsed = self.last_failure_at?.elapsed();
+        (elapsed < self.backoff).then(|| self.backoff - elapsed)
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.last_success_at = Some(Instant::now());
+        self.ejected_at = None;
+        self.backoff = INITIAL_BACKOFF;
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.last_failure_at = Some(Instant::now());
+        if self.consecutive_failures >= CONSECUTIVE_FAILURES_TO_EJECT {
+            self.ejected_at = Some(Instant::now());
+        }
+        self.backoff = jittered_backoff(self.backoff);
+    }
+}
+
+/// jittered_backoff doubles `previous` up to `MAX_BACKOFF`, with up to 20% jitter so a
+/// fleet of peers reconnecting to the same scheduler does not thunder in lockstep.
+fn jittered_backoff(previous: Duration) -> Duration {
+    let doubled = if previous.is_zero() {
+        INITIAL_BACKOFF
+    } else {
+        (previous * 2).min(MAX_BACKOFF)
+    };
+
+    let jitter_frac = rand::thread_rng().gen_range(0.0..0.2);
+    doubled.mul_f64(1.0 + jitter_frac)
+}
+
+/// normalize_addr prefixes `addr` with `http://` if it does not already carry a URI scheme.
+/// Candidate addresses come from config/`Dynconfig` as bare `host:port` pairs, but
+/// `Endpoint::from_shared` requires a full URI, so a bare address would otherwise fail to
+/// parse at connect time instead of at startup where the mistake is easier to catch.
+fn normalize_addr(addr: &str) -> String {
+    if addr.contains("://") {
+        addr.to_string()
+    } else {
+        format!("http://{}", addr)
+    }
+}
+
+/// is_failover_error reports whether a tonic status represents a transport-level failure
+/// that should trigger failover to another candidate address, as opposed to an
+/// application-level RPC error that the caller should see as-is.
+pub fn is_failover_error(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Cancelled | Code::DeadlineExceeded | Code::Aborted
+    )
+}
+
+/// ConnectionManager maintains a pool of candidate addresses for a single logical
+/// endpoint (the manager, or a scheduler cluster), performs lazy reconnection, and picks
+/// the next healthy candidate for each call.
+pub struct ConnectionManager {
+    channels: RwLock<HashMap<String, Channel>>,
+    health: RwLock<HashMap<String, EndpointHealth>>,
+    addrs: RwLock<Vec<String>>,
+}
+
+impl ConnectionManager {
+    /// new seeds the pool from the given candidate addresses (typically from config).
+    pub fn new(addrs: Vec<String>) -> Arc<Self> {
+        Arc::new(Self {
+            channels: RwLock::new(HashMap::new()),
+            health: RwLock::new(HashMap::new()),
+            addrs: RwLock::new(addrs),
+        })
+    }
+
+    /// update_addrs replaces the candidate address pool, used when `Dynconfig` refreshes
+    /// the known manager/scheduler addresses. Addresses no longer present are dropped;
+    /// addresses that are new are added without a connection, which is established lazily.
+    pub async fn update_addrs(&self, addrs: Vec<String>) {
+        let mut current = self.addrs.write().await;
+        if *current != addrs {
+            info!("connection manager addresses updated: {:?}", addrs);
+
+            let removed: Vec<String> = current
+                .iter()
+                .filter(|addr| !addrs.contains(addr))
+                .cloned()
+                .collect();
+            if !removed.is_empty() {
+                let mut channels = self.channels.write().await;
+                let mut health = self.health.write().await;
+                for addr in &removed {
+                    channels.remove(addr);
+                    health.remove(addr);
+                    let _ = ENDPOINT_EJECTED.remove_label_values(&[addr]);
+                }
+            }
+
+            *current = addrs;
+        }
+    }
+
+    /// healthy_addr picks the best candidate address to try next: preferring one that is
+    /// neither ejected nor still serving out its backoff window, and among those the one
+    /// that last succeeded most recently. Falls back to any address (even an ejected or
+    /// throttled one) if every candidate is currently unavailable, since serving a stale
+    /// connection beats serving none.
+    pub async fn healthy_addr(&self, exclude: &[String]) -> Option<String> {
+        let addrs = self.addrs.read().await;
+        let health = self.health.read().await;
+
+        let mut candidates: Vec<&String> = addrs.iter().filter(|a| !exclude.contains(a)).collect();
+        candidates.sort_by_key(|addr| {
+            let h = health.get(*addr);
+            (
+                h.map(|h| h.is_ejected()).unwrap_or(false),
+                h.map(|h| h.is_throttled()).unwrap_or(false),
+                std::cmp::Reverse(h.and_then(|h| h.last_success_at)),
+            )
+        });
+
+        candidates.into_iter().next().cloned()
+    }
+
+    /// get_or_connect returns a cached channel for `addr`, lazily connecting (with the
+    /// endpoint's own backoff respected) if there is none yet. With a single candidate
+    /// address (the common single-scheduler/single-manager deployment), `healthy_addr`'s
+    /// reordering of candidates never kicks in, so the backoff has to be enforced here
+    /// instead: a still-throttled `addr` is slept out before the connect attempt, rather
+    /// than retried immediately at zero delay.
+    pub async fn get_or_connect(&self, addr: &str) -> Result<Channel, tonic::transport::Error> {
+        if let Some(channel) = self.channels.read().await.get(addr) {
+            return Ok(channel.clone());
+        }
+
+        let remaining_backoff = self
+            .health
+            .read()
+            .await
+            .get(addr)
+            .and_then(|health| health.remaining_backoff());
+        if let Some(remaining_backoff) = remaining_backoff {
+            tokio::time::sleep(remaining_backoff).await;
+        }
+
+        let endpoint = Endpoint::from_shared(normalize_addr(addr))?;
+        let channel = endpoint.connect().await?;
+        self.channels
+            .write()
+            .await
+            .insert(addr.to_string(), channel.clone());
+        Ok(channel)
+    }
+
+    /// record_success marks `addr` healthy, resetting its failure count and backoff.
+    pub async fn record_success(&self, addr: &str) {
+        self.health
+            .write()
+            .await
+            .entry(addr.to_string())
+            .or_default()
+            .record_success();
+
+        ENDPOINT_CALL_TOTAL.with_label_values(&[addr, "success"]).inc();
+        ENDPOINT_EJECTED.with_label_values(&[addr]).set(0);
+    }
+
+    /// record_failure marks `addr` as having failed, ejecting it from the healthy
+    /// rotation once it crosses [`CONSECUTIVE_FAILURES_TO_EJECT`] consecutive failures,
+    /// and dropping its cached channel so the next attempt reconnects from scratch.
+    pub async fn record_failure(&self, addr: &str) {
+        let ejected = {
+            let mut health = self.health.write().await;
+            let entry = health.entry(addr.to_string()).or_default();
+            entry.record_failure();
+            entry.is_ejected()
+        };
+
+        self.channels.write().await.remove(addr);
+
+        ENDPOINT_CALL_TOTAL.with_label_values(&[addr, "failure"]).inc();
+        ENDPOINT_EJECTED
+            .with_label_values(&[addr])
+            .set(ejected as i64);
+
+        if ejected {
+            warn!("ejecting endpoint {} after repeated failures", addr);
+        }
+    }
+
+    /// connect_any establishes a connection to the best candidate address, trying the
+    /// remaining known addresses in `healthy_addr` order if the first choice is
+    /// unreachable. Used at client construction time so that one unreachable address does
+    /// not block startup when other candidates are healthy.
+    pub async fn connect_any(&self) -> Result<String, anyhow::Error> {
+        let mut excluded = Vec::new();
+        let mut last_err = None;
+
+        loop {
+            let Some(addr) = self.healthy_addr(&excluded).await else {
+                return Err(last_err
+                    .unwrap_or_else(|| anyhow::anyhow!("no candidate addresses configured")));
+            };
+
+            match self.get_or_connect(&addr).await {
+                Ok(_) => return Ok(addr),
+                Err(err) => {
+                    warn!("connect to {} failed: {}", addr, err);
+                    self.record_failure(&addr).await;
+                    last_err = Some(anyhow::Error::from(err));
+                    excluded.push(addr);
+                }
+            }
+        }
+    }
+
+    /// call_with_failover runs `call` against the best healthy candidate address,
+    /// recording the outcome and retrying against the next candidate when the error is a
+    /// transport-level failure (`UNAVAILABLE`, connection reset, etc.), until either the
+    /// call succeeds or every known address has been tried. Shared by `ManagerClient` and
+    /// `SchedulerClient` so the failover policy lives in one place.
+    pub async fn call_with_failover<T, F, Fut>(
+        &self,
+        endpoint_kind: &str,
+        mut call: F,
+    ) -> Result<T, tonic::Status>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: std::future::Future<Output = Result<T, tonic::Status>>,
+    {
+        let mut excluded = Vec::new();
+        loop {
+            let Some(addr) = self.healthy_addr(&excluded).await else {
+                return Err(tonic::Status::unavailable(format!(
+                    "no healthy {} address available",
+                    endpoint_kind
+                )));
+            };
+
+            let channel = match self.get_or_connect(&addr).await {
+                Ok(channel) => channel,
+                Err(err) => {
+                    warn!("connect to {} {} failed: {}", endpoint_kind, addr, err);
+                    self.record_failure(&addr).await;
+                    excluded.push(addr);
+                    continue;
+                }
+            };
+
+            match call(channel).await {
+                Ok(response) => {
+                    self.record_success(&addr).await;
+                    return Ok(response);
+                }
+                Err(status) if is_failover_error(&status) => {
+                    warn!(
+                        "{} rpc to {} failed, failing over: {}",
+                        endpoint_kind, addr, status
+                    );
+                    self.record_failure(&addr).await;
+                    excluded.push(addr);
+                }
+                Err(status) => return Err(status),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_addr_adds_scheme_to_bare_host_port() {
+        assert_eq!(normalize_addr("127.0.0.1:8002"), "http://127.0.0.1:8002");
+    }
+
+    #[test]
+    fn normalize_addr_leaves_an_existing_scheme_alone() {
+        assert_eq!(
+            normalize_addr("https://manager.example.com:443"),
+            "https://manager.example.com:443"
+        );
+    }
+
+    #[test]
+    fn is_failover_error_matches_transport_level_codes() {
+        assert!(is_failover_error(&tonic::Status::unavailable("down")));
+        assert!(is_failover_error(&tonic::Status::cancelled("cancelled")));
+        assert!(is_failover_error(&tonic::Status::deadline_exceeded(
+            "timeout"
+        )));
+        assert!(!is_failover_error(&tonic::Status::not_found("missing")));
+        assert!(!is_failover_error(&tonic::Status::invalid_argument(
+            "bad request"
+        )));
+    }
+
+    #[tokio::test]
+    async fn healthy_addr_prefers_the_most_recently_successful_candidate() {
+        let manager = ConnectionManager::new(vec!["a:1".to_string(), "b:1".to_string()]);
+        manager.record_success("a:1").await;
+        manager.record_success("b:1").await;
+
+        // "b:1" succeeded more recently, so it should be preferred.
+        assert_eq!(
+            manager.healthy_addr(&[]).await,
+            Some("b:1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn healthy_addr_deprioritizes_an_ejected_endpoint() {
+        let manager = ConnectionManager::new(vec!["a:1".to_string(), "b:1".to_string()]);
+        manager.record_success("a:1").await;
+        manager.record_success("b:1").await;
+
+        for _ in 0..CONSECUTIVE_FAILURES_TO_EJECT {
+            manager.record_failure("b:1").await;
+        }
+
+        // "b:1" is now ejected, even though it otherwise looked preferable, so "a:1" should
+        // be picked instead.
+        assert_eq!(
+            manager.healthy_addr(&[]).await,
+            Some("a:1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn healthy_addr_falls_back_to_an_ejected_endpoint_if_nothing_else_is_left() {
+        let manager = ConnectionManager::new(vec!["a:1".to_string()]);
+        for _ in 0..CONSECUTIVE_FAILURES_TO_EJECT {
+            manager.record_failure("a:1").await;
+        }
+
+        // Serving a stale/ejected connection beats serving none.
+        assert_eq!(
+            manager.healthy_addr(&[]).await,
+            Some("a:1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn healthy_addr_respects_the_exclude_list() {
+        let manager = ConnectionManager::new(vec!["a:1".to_string(), "b:1".to_string()]);
+        assert_eq!(
+            manager.healthy_addr(&["a:1".to_string()]).await,
+            Some("b:1".to_string())
+        );
+        assert_eq!(
+            manager
+                .healthy_addr(&["a:1".to_string(), "b:1".to_string()])
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn get_or_connect_waits_out_the_backoff_window_before_retrying() {
+        // A single candidate address never gets reordered behind anything else by
+        // `healthy_addr`, so the backoff has to come from `get_or_connect` itself; this is
+        // the case that previously retried at zero delay.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            loop {
+                if listener.accept().await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let manager = ConnectionManager::new(vec![addr.clone()]);
+        manager.record_failure(&addr).await;
+
+        let started_at = Instant::now();
+        manager
+            .get_or_connect(&addr)
+            .await
+            .expect("connect should succeed once the backoff elapses");
+
+        assert!(
+            started_at.elapsed() >= INITIAL_BACKOFF,
+            "get_or_connect returned before the backoff window it recorded had elapsed"
+        );
+    }
+
+    #[tokio::test]
+    async fn update_addrs_prunes_health_for_removed_addresses() {
+        let manager = ConnectionManager::new(vec!["a:1".to_string(), "b:1".to_string()]);
+        for _ in 0..CONSECUTIVE_FAILURES_TO_EJECT {
+            manager.record_failure("b:1").await;
+        }
+
+        manager.update_addrs(vec!["a:1".to_string()]).await;
+
+        // "b:1" is gone from the pool entirely, so it is neither a candidate nor still
+        // carrying its old (ejected) health state if it were ever reintroduced.
+        assert_eq!(manager.healthy_addr(&[]).await, Some("a:1".to_string()));
+        assert!(!manager.health.read().await.contains_key("b:1"));
+    }
+}
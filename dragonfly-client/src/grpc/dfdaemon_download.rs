@@ -0,0 +1,103 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::resource::persistent_cache_task::PersistentCacheTask;
+use crate::resource::task::Task;
+use crate::shutdown;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, Barrier};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+use tracing::{error, info};
+
+/// DfdaemonDownloadServer serves the gRPC service local clients (the `dfget` CLI and SDKs)
+/// use to trigger and track downloads, over a Unix domain socket rather than TCP, since its
+/// clients always run on the same host as the daemon.
+pub struct DfdaemonDownloadServer {
+    socket_path: PathBuf,
+    task: Arc<Task>,
+    persistent_cache_task: Arc<PersistentCacheTask>,
+    shutdown: shutdown::Shutdown,
+    shutdown_complete_tx: mpsc::UnboundedSender<()>,
+}
+
+impl DfdaemonDownloadServer {
+    /// new creates a new DfdaemonDownloadServer listening on `socket_path`.
+    pub fn new(
+        socket_path: PathBuf,
+        task: Arc<Task>,
+        persistent_cache_task: Arc<PersistentCacheTask>,
+        shutdown: shutdown::Shutdown,
+        shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    ) -> Self {
+        Self {
+            socket_path,
+            task,
+            persistent_cache_task,
+            shutdown,
+            shutdown_complete_tx,
+        }
+    }
+
+    /// run binds the unix socket gRPC listener, signals `started_barrier` once bound, and
+    /// serves download requests until shutdown is triggered. `barrier_reached` is flipped
+    /// only once this attempt's arrival actually lands, so the caller knows it is safe to
+    /// stop handing this worker the real `started_barrier` on a restart (reusing it after a
+    /// failed attempt that never arrived would otherwise wait on an arrival that will never
+    /// come, deadlocking the other grpc servers that already passed their own arrival).
+    pub async fn run(
+        &mut self,
+        started_barrier: Arc<Barrier>,
+        barrier_reached: Arc<AtomicBool>,
+    ) -> Result<(), anyhow::Error> {
+        // Remove a stale socket file left behind by an unclean shutdown; otherwise binding
+        // to the same path fails with `AddrInUse` even though nothing is listening on it.
+        if self.socket_path.exists() {
+            std::fs::remove_file(&self.socket_path)?;
+        }
+
+        if let Some(parent) = self.socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = UnixListener::bind(&self.socket_path).inspect_err(|err| {
+            error!("dfdaemon download grpc listener bind failed: {}", err);
+        })?;
+        info!(
+            "dfdaemon download grpc server listening on {:?}",
+            self.socket_path
+        );
+
+        let mut shutdown = self.shutdown.clone();
+        let shutdown_complete_tx = self.shutdown_complete_tx.clone();
+
+        let server = Server::builder().serve_with_incoming_shutdown(
+            UnixListenerStream::new(listener),
+            async move {
+                started_barrier.wait().await;
+                barrier_reached.store(true, Ordering::SeqCst);
+                let _ = shutdown.recv().await;
+                let _ = shutdown_complete_tx;
+            },
+        );
+
+        server.await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::grpc::connection_manager::ConnectionManager;
+use dragonfly_api::manager::v2::{
+    manager_client::ManagerClient as ManagerGRPCClient, KeepAliveRequest, ListSchedulersRequest,
+    ListSchedulersResponse, UpdateSeedPeerRequest, UpdateSeedPeerResponse,
+};
+use dragonfly_client_config::dfdaemon::Config;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+/// ManagerClient is a client for the manager's gRPC service. It resolves the manager's
+/// addresses once at construction, then relies on a [`ConnectionManager`] to keep a pool of
+/// candidate addresses alive and to fail an RPC over to the next healthy address on a
+/// transport error, so a transient manager outage does not require restarting the daemon.
+pub struct ManagerClient {
+    config: Arc<Config>,
+    connection_manager: Arc<ConnectionManager>,
+}
+
+impl ManagerClient {
+    /// new creates a new ManagerClient, seeding the connection manager with `addrs` (the
+    /// addresses configured under `manager.addr`) and eagerly connecting to whichever
+    /// candidate is reachable first, so startup still fails fast only when every
+    /// configured manager address is unreachable.
+    pub async fn new(config: Arc<Config>, addrs: Vec<String>) -> Result<Self, anyhow::Error> {
+        let connection_manager = ConnectionManager::new(addrs);
+        connection_manager.connect_any().await?;
+
+        Ok(Self {
+            config,
+            connection_manager,
+        })
+    }
+
+    /// with_failover runs `call` against the next healthy candidate address, retrying
+    /// against another candidate on a transport-level failure. See
+    /// [`ConnectionManager::call_with_failover`] for the shared retry policy.
+    async fn with_failover<T, F, Fut>(&self, call: F) -> Result<T, tonic::Status>
+    where
+        F: FnMut(tonic::transport::Channel) -> Fut,
+        Fut: std::future::Future<Output = Result<T, tonic::Status>>,
+    {
+        self.connection_manager
+            .call_with_failover("manager", call)
+            .await
+    }
+
+    /// refresh_addrs re-reads the manager addresses from `config.manager.addr` and updates
+    /// the connection manager's candidate pool. Intended to be polled periodically by a
+    /// background worker. The config is loaded once at startup, so this re-applies the same
+    /// list and leaves the pool unchanged.
+    pub async fn refresh_addrs(&self) {
+        self.connection_manager
+            .update_addrs(self.config.manager.addr.clone())
+            .await;
+    }
+
+    /// list_schedulers lists the scheduler clusters available to this peer, which
+    /// `Dynconfig` polls periodically to keep `SchedulerClient`'s candidate pool current.
+    pub async fn list_schedulers(
+        &self,
+        request: ListSchedulersRequest,
+    ) -> Result<ListSchedulersResponse, tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                ManagerGRPCClient::new(channel)
+                    .list_schedulers(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// update_seed_peer reports this seed peer's current state to the manager, so the
+    /// manager's scheduler cluster view reflects which seed peers are actually available.
+    pub async fn update_seed_peer(
+        &self,
+        request: UpdateSeedPeerRequest,
+    ) -> Result<UpdateSeedPeerResponse, tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                ManagerGRPCClient::new(channel)
+                    .update_seed_peer(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// keep_alive opens the long-lived bidirectional stream seed peers use to announce
+    /// liveness to the manager. Unlike the unary RPCs above, a broken stream is not
+    /// transparently retried here: `with_failover` fails a single call over to the next
+    /// candidate, but a stream's caller owns its own reconnect loop, so this connects
+    /// directly to the best currently-known candidate and lets the caller redrive
+    /// `keep_alive` (and thus re-resolve a candidate) if the stream ends.
+    pub async fn keep_alive(
+        &self,
+    ) -> Result<
+        (
+            mpsc::Sender<KeepAliveRequest>,
+            tonic::Streaming<dragonfly_api::common::v2::Empty>,
+        ),
+        tonic::Status,
+    > {
+        let addr = self
+            .connection_manager
+            .healthy_addr(&[])
+            .await
+            .ok_or_else(|| tonic::Status::unavailable("no healthy manager address available"))?;
+        let channel: Channel = self
+            .connection_manager
+            .get_or_connect(&addr)
+            .await
+            .map_err(|err| tonic::Status::unavailable(err.to_string()))?;
+
+        let (tx, rx) = mpsc::channel(10);
+        let response = ManagerGRPCClient::new(channel)
+            .keep_alive(ReceiverStream::new(rx))
+            .await;
+
+        match response {
+            Ok(response) => {
+                self.connection_manager.record_success(&addr).await;
+                Ok((tx, response.into_inner()))
+            }
+            Err(status) => {
+                self.connection_manager.record_failure(&addr).await;
+                Err(status)
+            }
+        }
+    }
+}
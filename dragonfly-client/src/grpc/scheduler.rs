@@ -0,0 +1,220 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::dynconfig::Dynconfig;
+use crate::grpc::connection_manager::ConnectionManager;
+use dragonfly_api::scheduler::v2::{
+    scheduler_client::SchedulerClient as SchedulerGRPCClient, AnnounceHostRequest,
+    AnnouncePeerRequest, AnnouncePeerResponse, DeleteTaskRequest, LeaveHostRequest,
+    LeaveTaskRequest, RegisterPeerRequest, RegisterPeerResponse, StatTaskRequest, Task,
+};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+/// SchedulerClient is a client for a scheduler cluster's gRPC service. Scheduler addresses
+/// are not fixed: `Dynconfig` refreshes them over time as schedulers are added, removed, or
+/// rotated. `SchedulerClient` keeps a [`ConnectionManager`] in sync with `Dynconfig` and
+/// fails individual RPCs over to the next healthy scheduler on transport errors, so a
+/// flapping or restarting scheduler node does not interrupt task scheduling. Piece
+/// scheduling itself rides the long-lived `announce_peer` stream rather than one of these
+/// unary RPCs; see [`SchedulerClient::announce_peer`] for how that stream survives a
+/// scheduler restart.
+pub struct SchedulerClient {
+    dynconfig: Arc<Dynconfig>,
+    connection_manager: Arc<ConnectionManager>,
+}
+
+impl SchedulerClient {
+    /// new creates a new SchedulerClient, seeding the connection manager from `Dynconfig`'s
+    /// currently known scheduler addresses.
+    pub async fn new(dynconfig: Arc<Dynconfig>) -> Result<Self, anyhow::Error> {
+        let addrs = dynconfig.scheduler_addrs().await?;
+        let connection_manager = ConnectionManager::new(addrs);
+        connection_manager.connect_any().await?;
+
+        Ok(Self {
+            dynconfig,
+            connection_manager,
+        })
+    }
+
+    /// refresh_addrs pulls the latest scheduler addresses from `Dynconfig` and updates the
+    /// connection manager's candidate pool. Intended to be polled periodically by a
+    /// background worker, so a scheduler cluster rotation `Dynconfig` has already picked up
+    /// is reflected here without needing to recreate the client.
+    pub async fn refresh_addrs(&self) -> Result<(), anyhow::Error> {
+        let addrs = self.dynconfig.scheduler_addrs().await?;
+        self.connection_manager.update_addrs(addrs).await;
+        Ok(())
+    }
+
+    /// with_failover runs `call` against the next healthy scheduler, retrying against
+    /// another candidate on a transport-level failure. See
+    /// [`ConnectionManager::call_with_failover`] for the shared retry policy.
+    async fn with_failover<T, F, Fut>(&self, call: F) -> Result<T, tonic::Status>
+    where
+        F: FnMut(tonic::transport::Channel) -> Fut,
+        Fut: std::future::Future<Output = Result<T, tonic::Status>>,
+    {
+        self.connection_manager
+            .call_with_failover("scheduler", call)
+            .await
+    }
+
+    /// register_peer registers a peer with the scheduler at the start of a download,
+    /// exchanging the task's identity for the scheduling metadata the peer needs to start
+    /// downloading pieces.
+    pub async fn register_peer(
+        &self,
+        request: RegisterPeerRequest,
+    ) -> Result<RegisterPeerResponse, tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .register_peer(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// announce_host reports this host's resource usage to the scheduler, which factors it
+    /// into peer selection for other downloaders.
+    pub async fn announce_host(&self, request: AnnounceHostRequest) -> Result<(), tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .announce_host(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// stat_task asks the scheduler for a task's current state, used to decide whether a
+    /// requested download can be served from an existing peer instead of starting over.
+    pub async fn stat_task(&self, request: StatTaskRequest) -> Result<Task, tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .stat_task(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// delete_task tells the scheduler a task has been evicted locally, so it stops
+    /// directing other peers here for it.
+    pub async fn delete_task(&self, request: DeleteTaskRequest) -> Result<(), tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .delete_task(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// leave_task tells the scheduler this peer is done participating in a task's download,
+    /// whether it finished, failed, or was cancelled, so the scheduler stops scheduling
+    /// pieces to or through it for that task.
+    pub async fn leave_task(&self, request: LeaveTaskRequest) -> Result<(), tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .leave_task(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// leave_host tells the scheduler this host is shutting down, so it stops scheduling any
+    /// task to it across every in-flight download.
+    pub async fn leave_host(&self, request: LeaveHostRequest) -> Result<(), tonic::Status> {
+        self.with_failover(|channel| {
+            let request = request.clone();
+            async move {
+                SchedulerGRPCClient::new(channel)
+                    .leave_host(request)
+                    .await
+                    .map(|response| response.into_inner())
+            }
+        })
+        .await
+    }
+
+    /// announce_peer opens the long-lived bidirectional stream a peer uses to receive its
+    /// piece-scheduling decisions from the scheduler for the duration of a download. Unlike
+    /// the unary RPCs above, a broken stream is not transparently retried here:
+    /// `with_failover` fails a single call over to the next candidate, but a stream's caller
+    /// owns its own reconnect loop, so this connects directly to the best currently-known
+    /// candidate and lets the caller redrive `announce_peer` (and thus re-resolve a
+    /// candidate, picking up any scheduler added or restarted in the meantime) if the stream
+    /// ends. This is the piece of the scheduler client that actually keeps an in-flight
+    /// download scheduled across a scheduler restart; the unary RPCs above only cover the
+    /// calls made around the edges of a download.
+    pub async fn announce_peer(
+        &self,
+    ) -> Result<
+        (
+            mpsc::Sender<AnnouncePeerRequest>,
+            tonic::Streaming<AnnouncePeerResponse>,
+        ),
+        tonic::Status,
+    > {
+        let addr = self
+            .connection_manager
+            .healthy_addr(&[])
+            .await
+            .ok_or_else(|| tonic::Status::unavailable("no healthy scheduler address available"))?;
+        let channel: Channel = self
+            .connection_manager
+            .get_or_connect(&addr)
+            .await
+            .map_err(|err| tonic::Status::unavailable(err.to_string()))?;
+
+        let (tx, rx) = mpsc::channel(10);
+        let response = SchedulerGRPCClient::new(channel)
+            .announce_peer(ReceiverStream::new(rx))
+            .await;
+
+        match response {
+            Ok(response) => {
+                self.connection_manager.record_success(&addr).await;
+                Ok((tx, response.into_inner()))
+            }
+            Err(status) => {
+                self.connection_manager.record_failure(&addr).await;
+                Err(status)
+            }
+        }
+    }
+}
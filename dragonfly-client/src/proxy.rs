@@ -0,0 +1,599 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::resource::task::Task;
+use crate::shutdown;
+#[cfg(feature = "http3-preview")]
+use crate::grpc::quic::{QuicConfig, QuicEndpoint};
+use dragonfly_client_config::dfdaemon::Config;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Barrier};
+use tracing::{error, info};
+
+/// Proxy serves as an HTTP forward proxy for client requests, passing each proxied request
+/// through to its origin (see [`handle_proxy_request`]). It listens over TCP by
+/// default; with the opt-in `http3-preview` feature it can additionally bind a QUIC
+/// endpoint (see [`crate::grpc::quic`]) so proxied requests can ride QUIC on lossy,
+/// high-latency links.
+pub struct Proxy {
+    addr: SocketAddr,
+    task: Arc<Task>,
+    shutdown: shutdown::Shutdown,
+    shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    #[cfg(feature = "http3-preview")]
+    quic_config: Option<QuicConfig>,
+}
+
+impl Proxy {
+    /// new creates a new Proxy listening on `config.proxy.server`'s address.
+    pub fn new(
+        config: Arc<Config>,
+        task: Arc<Task>,
+        shutdown: shutdown::Shutdown,
+        shutdown_complete_tx: mpsc::UnboundedSender<()>,
+    ) -> Self {
+        let addr = SocketAddr::new(
+            config.proxy.server.ip.unwrap(),
+            config.proxy.server.port,
+        );
+
+        Self {
+            addr,
+            task,
+            shutdown,
+            shutdown_complete_tx,
+            #[cfg(feature = "http3-preview")]
+            quic_config: None,
+        }
+    }
+
+    /// with_quic opts this proxy into also binding a QUIC endpoint alongside its TCP
+    /// listener.
+    #[cfg(feature = "http3-preview")]
+    pub fn with_quic(mut self, quic_config: QuicConfig) -> Self {
+        self.quic_config = Some(quic_config);
+        self
+    }
+
+    /// run binds the TCP listener, signals `started_barrier` once bound, and proxies
+    /// connections until shutdown is triggered. When a QUIC config is set, the QUIC
+    /// endpoint is bound and run in its own concurrently spawned task rather than inline: a
+    /// single task can only ever satisfy one of `started_barrier`'s N arrivals per round, so
+    /// the TCP and QUIC arrivals must come from two independent tasks, not as two sequential
+    /// waits in this one. `barrier_reached` is flipped only once this attempt's TCP arrival
+    /// actually lands, so the caller knows it is safe to stop handing this worker the real
+    /// `started_barrier` on a restart (reusing it after a failed attempt that never arrived
+    /// would otherwise wait on an arrival that will never come).
+    pub async fn run(
+        &mut self,
+        started_barrier: Arc<Barrier>,
+        barrier_reached: Arc<AtomicBool>,
+    ) -> Result<(), std::io::Error> {
+        let listener = TcpListener::bind(self.addr).await.inspect_err(|err| {
+            error!("proxy tcp listener bind failed: {}", err);
+        })?;
+        info!("proxy tcp server listening on {}", self.addr);
+
+        // Bind and spawn the QUIC listener's arrival as its own independent task *before*
+        // this task's own TCP arrival below: both must be concurrently pending on
+        // `started_barrier` at the same time, since a barrier round only completes once all
+        // N parties are waiting simultaneously.
+        #[cfg(feature = "http3-preview")]
+        if let Some(quic_config) = self.quic_config.clone() {
+            let started_barrier = started_barrier.clone();
+            let task = self.task.clone();
+            tokio::spawn(async move {
+                match QuicEndpoint::bind(quic_config).await {
+                    Ok(endpoint) => {
+                        info!("proxy quic server listening on {}", endpoint.local_addr());
+                        // This listener's own independent arrival for the round.
+                        started_barrier.wait().await;
+                        let endpoint = Arc::new(endpoint);
+                        let handler = move |request: Vec<u8>| {
+                            let task = task.clone();
+                            async move { handle_proxy_request(task, request).await }
+                        };
+                        if let Err(err) = endpoint.run(handler).await {
+                            error!("proxy quic server failed: {}", err);
+                        }
+                    }
+                    Err(err) => {
+                        error!("proxy quic endpoint bind failed: {}", err);
+                        // Arrive anyway: a bind failure must not leave `started_barrier`
+                        // permanently one arrival short for every other listener.
+                        started_barrier.wait().await;
+                    }
+                }
+            });
+        }
+
+        // This listener's own arrival for the round.
+        started_barrier.wait().await;
+        barrier_reached.store(true, Ordering::SeqCst);
+
+        let task = self.task.clone();
+        let mut shutdown = self.shutdown.clone();
+        let _shutdown_complete_tx = self.shutdown_complete_tx.clone();
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer_addr) = accepted?;
+                    let task = task.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_proxy_connection(task, stream).await {
+                            error!("proxy connection from {} failed: {}", peer_addr, err);
+                        }
+                    });
+                }
+                _ = shutdown.recv() => {
+                    info!("proxy server shutting down");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// handle_proxy_connection reads a single proxied request off `stream` and either tunnels it
+/// (for a `CONNECT` request) or writes back the response produced by `handle_proxy_request`.
+async fn handle_proxy_connection(
+    task: Arc<Task>,
+    mut stream: tokio::net::TcpStream,
+) -> Result<(), std::io::Error> {
+    let request = read_http_message(&mut stream).await?;
+
+    if let Some(authority) = connect_request_authority(&request) {
+        return handle_connect_tunnel(stream, &authority).await;
+    }
+
+    let response = handle_proxy_request(task, request).await;
+    stream.write_all(&response).await
+}
+
+/// connect_request_authority returns the target `host:port` if `request`'s request line is a
+/// `CONNECT host:port HTTP/1.1` tunnel request, the form browsers and other HTTP clients use
+/// to ask a forward proxy to open an HTTPS tunnel.
+fn connect_request_authority(request: &[u8]) -> Option<String> {
+    let (method, target) = request_line(request)?;
+    method.eq_ignore_ascii_case("CONNECT").then(|| target.to_string())
+}
+
+/// handle_connect_tunnel serves a `CONNECT` request by dialing `authority` and, once
+/// connected, replying to the client with `200 Connection Established` and then relaying raw
+/// bytes bidirectionally between the client and the origin until either side closes. The
+/// subsequent TLS handshake (and everything after it) is opaque to the proxy: unlike a plain
+/// proxied request, a `CONNECT` tunnel is not itself an HTTP request/response exchanged with
+/// the origin, so the tunneled bytes must be copied as-is rather than parsed with
+/// `read_http_message`.
+async fn handle_connect_tunnel(
+    mut client: tokio::net::TcpStream,
+    authority: &str,
+) -> Result<(), std::io::Error> {
+    let mut origin = match tokio::net::TcpStream::connect(authority).await {
+        Ok(origin) => origin,
+        Err(err) => {
+            client
+                .write_all(bad_gateway_response(&err.to_string()).as_slice())
+                .await?;
+            return Err(err);
+        }
+    };
+
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    tokio::io::copy_bidirectional(&mut client, &mut origin).await?;
+    Ok(())
+}
+
+/// handle_proxy_request is the shared request handler both the TCP listener and the QUIC
+/// listener dispatch into, so a proxied request looks identical to the rest of the daemon
+/// regardless of which transport it arrived over. It forwards the request verbatim to its
+/// target origin and returns the origin's response.
+///
+/// Routing a request through `Task`'s piece cache first, so a peer-to-peer hit can skip the
+/// origin entirely, is not wired up yet; today every proxied request is a plain passthrough.
+///
+/// This is only reachable for a plain (non-`CONNECT`) request: the TCP listener diverts a
+/// `CONNECT` request straight into [`handle_connect_tunnel`] before it ever reaches here,
+/// since tunneling needs the raw client connection itself, not a request/response pair. The
+/// QUIC listener has no such connection to hand back to the client, so a `CONNECT` request
+/// arriving over the QUIC preview listener is not tunneled; it falls through to
+/// `forward_proxy_request` below, whose `parse_request_authority` does not accept its
+/// authority-form target, and the client gets a 502 response.
+async fn handle_proxy_request(_task: Arc<Task>, request: Vec<u8>) -> Vec<u8> {
+    match forward_proxy_request(&request).await {
+        Ok(response) => response,
+        Err(err) => {
+            error!("proxy request forwarding failed: {}", err);
+            bad_gateway_response(&err.to_string())
+        }
+    }
+}
+
+/// forward_proxy_request parses the proxied request's target authority out of its request
+/// line and forwards the request verbatim to that origin over a fresh TCP connection,
+/// returning the origin's response bytes.
+async fn forward_proxy_request(request: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let authority = parse_request_authority(request).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "could not parse a target host from the proxied request",
+        )
+    })?;
+
+    let mut origin = tokio::net::TcpStream::connect(&authority).await?;
+    origin.write_all(request).await?;
+    origin.flush().await?;
+
+    read_http_message(&mut origin).await
+}
+
+/// parse_request_authority extracts the proxied request's target `host:port` from the
+/// absolute-form URI a forward proxy normally receives (`GET http://host:port/path
+/// HTTP/1.1`). A `CONNECT host:port HTTP/1.1` tunnel request is handled separately by
+/// [`connect_request_authority`]/[`handle_connect_tunnel`], before a request ever reaches
+/// this function.
+fn parse_request_authority(request: &[u8]) -> Option<String> {
+    let (_method, target) = request_line(request)?;
+    let uri: http::Uri = target.parse().ok()?;
+    // Only the absolute-form carries a scheme; an authority-form `CONNECT` target does not.
+    let scheme = uri.scheme_str()?;
+    let host = uri.host()?;
+    let port = uri.port_u16().unwrap_or(match scheme {
+        "https" => 443,
+        _ => 80,
+    });
+    Some(format!("{}:{}", host, port))
+}
+
+/// request_line splits an HTTP message's request line into its method and target, e.g.
+/// `("GET", "http://example.com/")` or `("CONNECT", "example.com:443")`.
+fn request_line(request: &[u8]) -> Option<(&str, &str)> {
+    let line_end = request
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(request.len());
+    let line = std::str::from_utf8(&request[..line_end]).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    Some((method, target))
+}
+
+/// read_http_message reads a single HTTP/1.1 message (a request or a response) off `stream`
+/// and returns its raw bytes, headers and all. Real HTTP peers keep the connection open
+/// waiting for their counterpart rather than half-closing once they are done writing, so
+/// framing has to come from the message itself: the header block is read up to the blank
+/// line that ends it, and the body length is then taken from `Content-Length` or decoded off
+/// a `Transfer-Encoding: chunked` stream, instead of reading until the peer closes the
+/// socket. A message with neither header is assumed to carry no body, matching how requests
+/// without one are framed (and how a compliant HTTP/1.1 response that wants the connection
+/// kept alive is required to declare its length).
+async fn read_http_message<S>(stream: &mut S) -> Result<Vec<u8>, std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(pos) = find_header_end(&buf) {
+            break pos;
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return if buf.is_empty() {
+                Ok(buf)
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed while reading http headers",
+                ))
+            };
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    if is_chunked(&buf[..header_end]) {
+        read_chunked_body(stream, &mut buf, header_end).await?;
+    } else if let Some(content_length) = content_length(&buf[..header_end]) {
+        let body_end = header_end + content_length;
+        while buf.len() < body_end {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before the declared content-length was read",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        // Anything past the declared body is the start of the next pipelined message, not
+        // part of this one.
+        buf.truncate(body_end);
+    } else {
+        buf.truncate(header_end);
+    }
+
+    Ok(buf)
+}
+
+/// find_header_end returns the byte offset just past the blank line (`\r\n\r\n`) that
+/// terminates an HTTP message's header block, if `buf` contains one yet.
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// header_value looks up `name` (case-insensitively) among the `\r\n`-separated header lines
+/// in `headers` and returns its trimmed value.
+fn header_value<'a>(headers: &'a [u8], name: &str) -> Option<&'a str> {
+    let headers = std::str::from_utf8(headers).ok()?;
+    headers.split("\r\n").find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// content_length reads the `Content-Length` header out of `headers`, if present and valid.
+fn content_length(headers: &[u8]) -> Option<usize> {
+    header_value(headers, "content-length")?.parse().ok()
+}
+
+/// is_chunked reports whether `headers` declares `Transfer-Encoding: chunked`, which takes
+/// priority over `Content-Length` per RFC 9112.
+fn is_chunked(headers: &[u8]) -> bool {
+    header_value(headers, "transfer-encoding")
+        .map(|value| value.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+/// read_chunked_body reads a `Transfer-Encoding: chunked` body off `stream` into `buf`,
+/// whose first `header_end` bytes are the message's already-read headers, keeping the body
+/// in its raw chunked form (size lines, chunk data and the final trailer section) and
+/// truncating `buf` at the end of the message.
+async fn read_chunked_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    header_end: usize,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut pos = header_end;
+    loop {
+        let line_end = read_until_crlf(stream, buf, pos).await?;
+        let size_line = std::str::from_utf8(&buf[pos..line_end - 2])
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let size_str = size_line.split(';').next().unwrap_or(size_line).trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        pos = line_end;
+
+        if size == 0 {
+            // The final chunk is followed by an (often empty) trailer section and the
+            // message-terminating blank line.
+            loop {
+                let trailer_end = read_until_crlf(stream, buf, pos).await?;
+                let is_blank_line = trailer_end - pos == 2;
+                pos = trailer_end;
+                if is_blank_line {
+                    buf.truncate(pos);
+                    return Ok(());
+                }
+            }
+        }
+
+        let chunk_end = pos + size;
+        read_at_least(stream, buf, chunk_end).await?;
+        pos = chunk_end;
+        // Each chunk's data is followed by a trailing CRLF before the next chunk's size line.
+        let crlf_end = read_until_crlf(stream, buf, pos).await?;
+        pos = crlf_end;
+    }
+}
+
+/// read_until_crlf ensures `buf` holds at least through the next `\r\n` starting at `from`,
+/// reading more off `stream` as needed, and returns the offset just past that `\r\n`.
+async fn read_until_crlf<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    from: usize,
+) -> Result<usize, std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(pos) = buf[from..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        {
+            return Ok(from + pos + 2);
+        }
+        read_more(stream, buf).await?;
+    }
+}
+
+/// read_at_least ensures `buf` holds at least `len` bytes, reading more off `stream` as
+/// needed.
+async fn read_at_least<S>(stream: &mut S, buf: &mut Vec<u8>, len: usize) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    while buf.len() < len {
+        read_more(stream, buf).await?;
+    }
+    Ok(())
+}
+
+/// read_more reads a single chunk off `stream`, appending it to `buf`, and errors out if the
+/// peer closed the connection mid-body.
+async fn read_more<S>(stream: &mut S, buf: &mut Vec<u8>) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 8192];
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed while reading chunked http body",
+        ));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+/// bad_gateway_response builds a minimal HTTP/1.1 502 response carrying `reason` as its body,
+/// returned to the client when `reason`'s origin could not be reached or parsed.
+fn bad_gateway_response(reason: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 502 Bad Gateway\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\r\n{}",
+        reason.len(),
+        reason
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// send_and_read writes `data` (split into two separate writes, to exercise reads that
+    /// span more than one `poll_read`) into one end of an in-memory duplex stream and runs
+    /// `read_http_message` against the other end, returning what it parsed.
+    async fn send_and_read(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let split = data.len() / 2;
+        let (head, tail) = data.split_at(split.max(1).min(data.len()));
+        let head = head.to_vec();
+        let tail = tail.to_vec();
+        tokio::spawn(async move {
+            writer.write_all(&head).await.unwrap();
+            writer.write_all(&tail).await.unwrap();
+        });
+        read_http_message(&mut reader).await
+    }
+
+    #[tokio::test]
+    async fn read_http_message_reads_a_content_length_body() {
+        let request = b"POST /piece HTTP/1.1\r\nhost: example.com\r\ncontent-length: 5\r\n\r\nhello";
+        let message = send_and_read(request).await.unwrap();
+        assert_eq!(message, request);
+    }
+
+    #[tokio::test]
+    async fn read_http_message_ignores_bytes_past_the_declared_content_length() {
+        let request = b"POST /piece HTTP/1.1\r\ncontent-length: 5\r\n\r\nhelloGARBAGE";
+        let message = send_and_read(request).await.unwrap();
+        assert_eq!(
+            message,
+            b"POST /piece HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_http_message_reads_a_message_with_no_body() {
+        let request = b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n";
+        let message = send_and_read(request).await.unwrap();
+        assert_eq!(message, request);
+    }
+
+    #[tokio::test]
+    async fn read_http_message_errors_on_a_truncated_content_length_body() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            writer
+                .write_all(b"POST / HTTP/1.1\r\ncontent-length: 10\r\n\r\nshort")
+                .await
+                .unwrap();
+            // Dropping `writer` here closes the stream before the declared body arrives.
+        });
+        let err = read_http_message(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn read_http_message_reads_a_chunked_body() {
+        let request =
+            b"POST /piece HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let message = send_and_read(request).await.unwrap();
+        assert_eq!(message, request);
+    }
+
+    #[tokio::test]
+    async fn read_http_message_reads_a_chunked_body_with_multiple_chunks_and_a_trailer() {
+        let request = b"POST /piece HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n2\r\nhe\r\n3\r\nllo\r\n0\r\nx-trailer: ok\r\n\r\n";
+        let message = send_and_read(request).await.unwrap();
+        assert_eq!(message, request);
+    }
+
+    #[test]
+    fn parse_request_authority_handles_absolute_form_uri() {
+        let request = b"GET http://example.com/piece HTTP/1.1\r\nhost: example.com\r\n\r\n";
+        assert_eq!(
+            parse_request_authority(request),
+            Some("example.com:80".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_request_authority_defaults_https_to_port_443() {
+        let request = b"GET https://example.com/piece HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            parse_request_authority(request),
+            Some("example.com:443".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_request_authority_respects_an_explicit_port() {
+        let request = b"GET http://example.com:8080/piece HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            parse_request_authority(request),
+            Some("example.com:8080".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_request_authority_returns_none_for_a_connect_request() {
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        assert_eq!(parse_request_authority(request), None);
+    }
+
+    #[test]
+    fn connect_request_authority_parses_a_connect_request() {
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            connect_request_authority(request),
+            Some("example.com:443".to_string())
+        );
+    }
+
+    #[test]
+    fn connect_request_authority_returns_none_for_a_plain_request() {
+        let request = b"GET http://example.com/piece HTTP/1.1\r\n\r\n";
+        assert_eq!(connect_request_authority(request), None);
+    }
+}
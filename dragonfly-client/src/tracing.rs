@@ -0,0 +1,304 @@
+/*
+ *     Copyright 2023 The Dragonfly Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use dragonfly_client_config::dfdaemon::{TracingKafkaConfig, TracingProtocol};
+use opentelemetry::trace::TraceError;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::trace::{BatchConfig, BatchSpanProcessor, TracerProvider};
+use rdkafka::producer::{BaseRecord, ThreadedProducer};
+use rdkafka::ClientConfig;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tracing::{error, info, Level};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// TracingGuards holds the guards returned by `init_tracing`; dropping them flushes any
+/// buffered log/trace data on shutdown.
+pub struct TracingGuards {
+    _file_guard: Option<tracing_appender::non_blocking::WorkerGuard>,
+    _tracer_provider: Option<TracerProvider>,
+}
+
+/// init_tracing initializes the global tracing subscriber, wiring up file/console logging
+/// plus, when a tracing protocol is configured, an exporter that ships spans to either an
+/// OTLP collector or a Kafka topic (`kafka`). Every protocol other than `kafka` uses the
+/// gRPC (tonic) OTLP exporter; there is no separate OTLP/HTTP exporter.
+///
+/// The Kafka path exists because pushing spans directly to a collector endpoint per node
+/// does not scale to large fleets: a flaky or overloaded collector stalls every daemon
+/// talking to it. Producing batches to Kafka instead decouples the daemon from collector
+/// availability, at the cost of needing a consumer on the other end.
+#[allow(clippy::too_many_arguments)]
+pub fn init_tracing(
+    name: &str,
+    log_dir: PathBuf,
+    log_level: Level,
+    log_max_files: usize,
+    protocol: Option<TracingProtocol>,
+    endpoint: Option<String>,
+    path: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    host: Option<String>,
+    is_seed_peer: bool,
+    console: bool,
+) -> TracingGuards {
+    let file_appender = tracing_appender::rolling::Builder::new()
+        .rotation(tracing_appender::rolling::Rotation::DAILY)
+        .filename_prefix(name)
+        .filename_suffix("log")
+        .max_log_files(log_max_files)
+        .build(&log_dir)
+        .expect("failed to initialize rolling file appender");
+    let (non_blocking, file_guard) = tracing_appender::non_blocking(file_appender);
+
+    let env_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(log_level.to_string()));
+
+    let file_layer = fmt::layer().with_writer(non_blocking).with_ansi(false);
+    let console_layer = console.then(|| fmt::layer().with_writer(std::io::stdout));
+
+    let (tracer, tracer_provider) = match protocol {
+        Some(TracingProtocol::Kafka(kafka)) => match install_kafka_exporter(&kafka, host.as_deref())
+        {
+            Ok((tracer, provider)) => {
+                info!("tracing spans are exported to kafka topic {}", kafka.topic);
+                (Some(tracer), Some(provider))
+            }
+            Err(err) => {
+                error!("install kafka span exporter failed: {}", err);
+                (None, None)
+            }
+        },
+        Some(_) => match install_otlp_exporter(name, endpoint, path, headers, is_seed_peer) {
+            Ok((tracer, provider)) => {
+                info!("tracing spans are exported over otlp");
+                (Some(tracer), Some(provider))
+            }
+            Err(err) => {
+                error!("install otlp span exporter failed: {}", err);
+                (None, None)
+            }
+        },
+        None => (None, None),
+    };
+    let otel_layer = tracer.map(tracing_opentelemetry::layer);
+
+    tracing_subscriber::registry()
+        .with(env_filter)
+        .with(file_layer)
+        .with(console_layer)
+        .with(otel_layer)
+        .init();
+
+    TracingGuards {
+        _file_guard: Some(file_guard),
+        _tracer_provider: tracer_provider,
+    }
+}
+
+/// install_otlp_exporter builds a batching span exporter that ships finished spans to an
+/// OTLP collector over `endpoint` using gRPC, the default path when no Kafka indirection is
+/// configured.
+fn install_otlp_exporter(
+    name: &str,
+    endpoint: Option<String>,
+    path: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    is_seed_peer: bool,
+) -> Result<(opentelemetry_sdk::trace::Tracer, TracerProvider), TraceError> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let endpoint = endpoint.unwrap_or_default();
+    let mut exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(format!("{}{}", endpoint, path.unwrap_or_default()));
+    if let Some(headers) = headers {
+        exporter = exporter.with_metadata(opentelemetry_otlp::tonic_types::metadata::MetadataMap::from_headers(
+            (&headers)
+                .try_into()
+                .map_err(|err: http::Error| TraceError::from(err.to_string()))?,
+        ));
+    }
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new(vec![
+                KeyValue::new("service.name", name.to_string()),
+                KeyValue::new("service.is_seed_peer", is_seed_peer),
+            ]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    let provider = tracer.provider().ok_or_else(|| {
+        TraceError::from("otlp tracer has no associated provider".to_string())
+    })?;
+    Ok((tracer, provider))
+}
+
+/// install_kafka_exporter builds a batching span exporter that serializes each finished
+/// span batch and produces it to the configured Kafka topic, keyed by host id so that all
+/// spans from one daemon land on the same partition and preserve ordering downstream.
+fn install_kafka_exporter(
+    config: &TracingKafkaConfig,
+    host_id: Option<&str>,
+) -> Result<(opentelemetry_sdk::trace::Tracer, TracerProvider), TraceError> {
+    let producer: ThreadedProducer<_> = ClientConfig::new()
+        .set("bootstrap.servers", config.brokers.join(","))
+        .set("acks", config.acks.clone().unwrap_or_else(|| "1".to_string()))
+        .set(
+            "max.in.flight.requests.per.connection",
+            config.max_in_flight.unwrap_or(5).to_string(),
+        )
+        .create()
+        .map_err(|err| TraceError::from(err.to_string()))?;
+
+    let exporter = KafkaSpanExporter {
+        producer,
+        topic: config.topic.clone(),
+        key: host_id.unwrap_or_default().to_string(),
+        overflow_policy: config.overflow_policy,
+    };
+
+    opentelemetry::global::set_error_handler(|err| {
+        error!("kafka span exporter error: {}", err);
+    })
+    .ok();
+
+    let batch_processor = BatchSpanProcessor::builder(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_batch_config(BatchConfig::default())
+        .build();
+
+    let provider = TracerProvider::builder()
+        .with_span_processor(batch_processor)
+        .build();
+    let tracer = opentelemetry::trace::TracerProvider::tracer(&provider, "dfdaemon");
+
+    Ok((tracer, provider))
+}
+
+/// KafkaOverflowPolicy controls what happens to a finished span batch when the in-memory
+/// queue feeding the Kafka producer is full, so trace export backpressure never stalls
+/// downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KafkaOverflowPolicy {
+    /// DropNewest discards the batch that was about to be enqueued when the producer's
+    /// queue is full, leaving whatever is already queued to drain as-is. `rdkafka`'s
+    /// `ThreadedProducer` does not expose a way to peek or evict its oldest queued message,
+    /// so evicting the queue's actual oldest entry to make room is not implementable here.
+    DropNewest,
+
+    /// Block waits for room in the queue, up to a short timeout, before dropping.
+    Block,
+}
+
+/// KafkaSpanExporter serializes finished span batches (protobuf) and produces them to a
+/// Kafka topic, keyed by host id.
+struct KafkaSpanExporter {
+    producer: ThreadedProducer<rdkafka::producer::DefaultProducerContext>,
+    topic: String,
+    key: String,
+    overflow_policy: KafkaOverflowPolicy,
+}
+
+/// BLOCK_RETRY_INTERVAL is how long `KafkaOverflowPolicy::Block` waits between retrying a
+/// rejected enqueue.
+const BLOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// BLOCK_RETRY_TIMEOUT is how long `KafkaOverflowPolicy::Block` keeps retrying before giving
+/// up and dropping the batch anyway, so a producer queue that never drains cannot stall span
+/// export indefinitely.
+const BLOCK_RETRY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+impl SpanExporter for KafkaSpanExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> futures::future::BoxFuture<'static, ExportResult> {
+        let payload = encode_span_batch(&batch);
+        let topic = self.topic.clone();
+        let key = self.key.clone();
+        let overflow_policy = self.overflow_policy;
+        let producer = self.producer.clone();
+
+        Box::pin(async move {
+            let attempted_at = std::time::Instant::now();
+            loop {
+                let record = BaseRecord::to(&topic).payload(&payload).key(&key);
+                match producer.send(record) {
+                    Ok(()) => return Ok(()),
+                    Err((err, _)) if overflow_policy == KafkaOverflowPolicy::Block => {
+                        if attempted_at.elapsed() >= BLOCK_RETRY_TIMEOUT {
+                            error!(
+                                "dropping span batch, kafka producer queue still full after {:?}: {}",
+                                BLOCK_RETRY_TIMEOUT, err
+                            );
+                            return Ok(());
+                        }
+                        tokio::time::sleep(BLOCK_RETRY_INTERVAL).await;
+                    }
+                    Err((err, _)) => {
+                        error!("dropping span batch, kafka producer queue full: {}", err);
+                        return Ok(());
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// encode_span_batch serializes a batch of finished spans to protobuf for transport over
+/// Kafka, mirroring the wire format the OTLP exporter would otherwise send directly to a
+/// collector.
+fn encode_span_batch(batch: &[SpanData]) -> Vec<u8> {
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+    use prost::Message;
+
+    let resource_spans = group_spans_by_resource_and_scope(batch.to_vec(), &Default::default());
+    let request = ExportTraceServiceRequest { resource_spans };
+    request.encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kafka_overflow_policy_variants_are_distinct() {
+        assert_ne!(KafkaOverflowPolicy::DropNewest, KafkaOverflowPolicy::Block);
+        assert_eq!(KafkaOverflowPolicy::Block, KafkaOverflowPolicy::Block);
+    }
+
+    #[test]
+    fn block_retry_interval_fits_within_the_timeout() {
+        // Otherwise `Block` would never actually retry before giving up.
+        assert!(BLOCK_RETRY_INTERVAL < BLOCK_RETRY_TIMEOUT);
+    }
+
+    #[test]
+    fn encode_span_batch_of_empty_batch_is_a_valid_empty_request() {
+        use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+        use prost::Message;
+
+        let encoded = encode_span_batch(&[]);
+        let decoded = ExportTraceServiceRequest::decode(encoded.as_slice())
+            .expect("encoded batch should round-trip through protobuf decoding");
+        assert!(decoded.resource_spans.is_empty());
+    }
+}